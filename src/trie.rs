@@ -2,26 +2,138 @@
 // TODO: write docs
 
 use alloc::collections::BTreeMap;
+use core::cell::RefCell;
 use core::convert::TryFrom as _;
-use hashbrown::{hash_map::Entry, HashMap};
-use parity_scale_codec::Encode as _;
+use core::marker::PhantomData;
+use hashbrown::HashMap;
+use parity_scale_codec::{Decode as _, Encode as _};
+
+/// Hashing algorithm used to turn node values into their fixed-size Merkle values.
+///
+/// Implementors only provide the raw 32-byte hash; the inline-vs-hashed threshold is shared across
+/// all of them and is applied by [`Trie::merkle_value`] and, for proofs, by [`merkle_value_of`].
+pub trait HashFunction {
+    /// Hashes `data` into a 32-byte digest.
+    fn hash(data: &[u8]) -> [u8; 32];
+
+    /// Hashes the concatenation of `chunks` into a 32-byte digest, without materializing the
+    /// concatenation in a single buffer. The default implementation falls back to [`Self::hash`]
+    /// over a collected buffer; hashers that support incremental updates should override it.
+    fn hash_chunks(chunks: impl Iterator<Item = impl AsRef<[u8]>>) -> [u8; 32] {
+        let mut data = Vec::new();
+        for chunk in chunks {
+            data.extend_from_slice(chunk.as_ref());
+        }
+        Self::hash(&data)
+    }
+}
+
+/// Blake2b with a 32-byte output, as used by Substrate.
+pub enum Blake2b256 {}
+
+impl HashFunction for Blake2b256 {
+    fn hash(data: &[u8]) -> [u8; 32] {
+        let blake2_hash = blake2_rfc::blake2b::blake2b(32, &[], data);
+        let mut out = [0; 32];
+        out.copy_from_slice(blake2_hash.as_bytes());
+        out
+    }
+
+    fn hash_chunks(chunks: impl Iterator<Item = impl AsRef<[u8]>>) -> [u8; 32] {
+        let mut state = blake2_rfc::blake2b::Blake2b::new(32);
+        for chunk in chunks {
+            state.update(chunk.as_ref());
+        }
+        let mut out = [0; 32];
+        out.copy_from_slice(state.finalize().as_bytes());
+        out
+    }
+}
+
+/// Keccak-256, as used by Ethereum.
+pub enum Keccak256 {}
+
+impl HashFunction for Keccak256 {
+    fn hash(data: &[u8]) -> [u8; 32] {
+        keccak256(core::iter::once(data))
+    }
+
+    fn hash_chunks(chunks: impl Iterator<Item = impl AsRef<[u8]>>) -> [u8; 32] {
+        keccak256(chunks)
+    }
+}
+
+/// Error that can happen while checking a Merkle proof with [`verify_proof`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProofError {
+    /// A node value in the proof doesn't hash to the value expected by its parent (or the root).
+    HashMismatch,
+    /// A node value in the proof couldn't be decoded.
+    MalformedNode,
+    /// The proof ended before reaching the node owning the requested key.
+    Incomplete,
+}
+
+/// Nibble position of a node, as used to key the memoized Merkle values: the byte key floor,
+/// paired with the trailing extra nibble when the position isn't byte-aligned.
+type NodePosition = (Vec<u8>, Option<u8>);
 
 /// Radix-16 Merkle-Patricia trie.
-pub struct Trie {
+///
+/// The `H` type parameter selects the hashing algorithm used to compute Merkle values, defaulting
+/// to Substrate's [`Blake2b256`]. Use [`Keccak256`] for Ethereum-style roots.
+pub struct Trie<H = Blake2b256> {
     entries: BTreeMap<Vec<u8>, Vec<u8>>,
+    /// Memoized Merkle values, keyed by node position. Kept in sync with `entries` by the mutation
+    /// methods, which evict the entries along the affected nibble path.
+    cache: RefCell<HashMap<NodePosition, [u8; 32]>>,
+    marker: PhantomData<fn() -> H>,
+}
+
+impl<H: HashFunction> Default for Trie<H> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-impl Trie {
+impl<H: HashFunction> Trie<H> {
     /// Builds a new empty [`Trie`].
-    pub fn new() -> Trie {
+    pub fn new() -> Trie<H> {
         Trie {
             entries: BTreeMap::new(),
+            cache: RefCell::new(HashMap::new()),
+            marker: PhantomData,
         }
     }
 
     /// Inserts a new entry in the trie.
     pub fn insert(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) {
-        self.entries.insert(key.into(), value.into());
+        let key = key.into();
+        self.invalidate_ancestors(&key);
+        self.entries.insert(key, value.into());
+    }
+
+    /// Returns the value stored at `key`, if any.
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.entries.get(key).map(Vec::as_slice)
+    }
+
+    /// Returns true if an entry is stored at `key`.
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    /// Removes the entry stored at `key` and returns its value, if any.
+    pub fn remove(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        self.invalidate_ancestors(key);
+        self.entries.remove(key)
+    }
+
+    /// Iterates over all the entries of the trie, ordered by key.
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &[u8])> {
+        self.entries
+            .iter()
+            .map(|(key, value)| (key.as_slice(), value.as_slice()))
     }
 
     /// Returns true if the `Trie` is empty.
@@ -32,6 +144,7 @@ impl Trie {
     /// Removes all the elements from the trie.
     pub fn clear(&mut self) {
         self.entries.clear();
+        self.cache.get_mut().clear();
     }
 
     /// Removes from the trie all the keys that start with `prefix`, including `prefix` itself.
@@ -50,6 +163,8 @@ impl Trie {
         for to_remove in to_remove {
             self.entries.remove(&to_remove);
         }
+
+        self.invalidate_prefix(prefix);
     }
 
     /// Calculates the Merkle value of the root node.
@@ -59,27 +174,108 @@ impl Trie {
 
     /// Calculates the Merkle value of the node with the given key.
     pub fn merkle_value(&self, key: &[u8], key_extra_nibble: Option<u8>) -> [u8; 32] {
-        let node_value = self.node_value(key, key_extra_nibble);
+        let cache_key = (key.to_vec(), key_extra_nibble);
+        if let Some(cached) = self.cache.borrow().get(&cache_key) {
+            return *cached;
+        }
 
-        if (key.is_empty() && key_extra_nibble.is_none()) || node_value.len() >= 32 {
-            let blake2_hash = blake2_rfc::blake2b::blake2b(32, &[], &node_value);
-            let mut out = [0; 32];
-            out.copy_from_slice(blake2_hash.as_bytes());
-            out
+        // The node value is streamed as a sequence of buffers rather than a single concatenated
+        // `Vec`. Only the pieces are materialized; they are then fed straight into the hasher.
+        let pieces = self.node_value(key, key_extra_nibble).collect::<Vec<_>>();
+        let total_len = pieces.iter().map(Vec::len).sum::<usize>();
+        let is_root = key.is_empty() && key_extra_nibble.is_none();
+
+        let merkle_value = if is_root || total_len >= 32 {
+            H::hash_chunks(pieces.iter())
         } else {
-            debug_assert!(node_value.len() < 32);
             let mut out = [0; 32];
-            // TODO: specs mention that the return value is always 32bits, but are unclear how to
-            // extend a less than 32bits value to 32bits
-            out[(32 - node_value.len())..].copy_from_slice(&node_value);
+            let mut offset = 32 - total_len;
+            for piece in &pieces {
+                out[offset..offset + piece.len()].copy_from_slice(piece);
+                offset += piece.len();
+            }
             out
+        };
+
+        self.cache.borrow_mut().insert(cache_key, merkle_value);
+        merkle_value
+    }
+
+    /// Evicts from the cache the Merkle values of every node on the path from the root to `key`,
+    /// i.e. the nodes whose value depends on the entry stored at `key`.
+    fn invalidate_ancestors(&mut self, key: &[u8]) {
+        let nibbles = bytes_to_nibbles(key);
+        let cache = self.cache.get_mut();
+        for depth in 0..=nibbles.len() {
+            let (prefix, extra) = position_from_nibbles(&nibbles[..depth]);
+            cache.remove(&(prefix, extra));
         }
     }
 
-    fn node_value(&self, key: &[u8], key_extra_nibble: Option<u8>) -> Vec<u8> {
+    /// Evicts from the cache every node lying under `prefix` as well as its ancestors, the two sets
+    /// of nodes whose Merkle value can change when a whole subtree is removed.
+    fn invalidate_prefix(&mut self, prefix: &[u8]) {
+        let prefix_nibbles = bytes_to_nibbles(prefix);
+        self.cache.get_mut().retain(|(key, extra), _| {
+            let position = node_prefix_nibbles(key, *extra);
+            !(position.starts_with(&prefix_nibbles) || prefix_nibbles.starts_with(&position))
+        });
+    }
+
+    /// Produces a Merkle proof of the value stored at `key` (or of its absence).
+    ///
+    /// The returned list is the ordered sequence of node values (as produced by
+    /// [`Trie::node_value`]) on the path from the root down to the node owning `key`. A verifier
+    /// holding only the 32-byte root can re-hash them bottom-up with [`verify_proof`].
+    pub fn prove(&self, key: &[u8]) -> Vec<Vec<u8>> {
+        let key_nibbles = bytes_to_nibbles(key);
+        let mut proof = Vec::new();
+        let mut depth = 0;
+
+        loop {
+            let (prefix, extra) = position_from_nibbles(&key_nibbles[..depth]);
+            proof.push(self.node_value(&prefix, extra).flatten().collect());
+
+            // Consume the node's partial key. If it runs past the key or diverges from it, the key
+            // isn't in the trie and this node is the last one of the non-membership proof.
+            let partial_key = self.node_partial_key_nibbles(&prefix, extra);
+            let end = depth + partial_key.len();
+            if end > key_nibbles.len() || key_nibbles[depth..end] != partial_key[..] {
+                break;
+            }
+
+            if end == key_nibbles.len() {
+                break;
+            }
+
+            // Descend into the child dictated by the next nibble of the key. If it isn't populated
+            // the walk stops here, which yields a non-membership proof.
+            let (end_key, end_extra) = position_from_nibbles(&key_nibbles[..end]);
+            let child = key_nibbles[end];
+            if self.node_children_bitmap(&end_key, end_extra) & (1 << (15 - child)) == 0 {
+                break;
+            }
+            depth = end + 1;
+        }
+
+        proof
+    }
+
+    /// Returns the node value as a sequence of byte buffers whose concatenation is the encoded node.
+    ///
+    /// All the pieces are computed before the iterator is returned. Keeping them separate lets
+    /// [`Trie::merkle_value`] feed them into the hasher one at a time instead of concatenating them
+    /// into a single large `Vec` per node.
+    fn node_value(&self, key: &[u8], key_extra_nibble: Option<u8>) -> impl Iterator<Item = Vec<u8>> {
+        core::iter::once(self.node_header(key, key_extra_nibble))
+            .chain(core::iter::once(self.node_partial_key(key, key_extra_nibble)))
+            .chain(self.node_subvalue(key, key_extra_nibble))
+    }
+
+    fn node_partial_key(&self, key: &[u8], key_extra_nibble: Option<u8>) -> Vec<u8> {
         let partial_key_nibbles = self.node_partial_key_nibbles(key, key_extra_nibble);
 
-        let partial_key = if partial_key_nibbles.len() % 2 == 0 {
+        if partial_key_nibbles.len().is_multiple_of(2) {
             let mut pk = Vec::with_capacity(partial_key_nibbles.len() / 2);
             for chunk in partial_key_nibbles.chunks(2) {
                 pk.push((chunk[0] << 4) | chunk[1]);
@@ -93,18 +289,22 @@ impl Trie {
                 pk.push((chunk[0] << 4) | chunk[1]);
             }
             pk
-        };
-        
-        let mut out = self.node_header(key, key_extra_nibble);
-        out.extend(partial_key);
-        out.extend(self.node_subvalue(key, key_extra_nibble));
-        out
+        }
+    }
+
+    /// Nibble position at the end of this node's partial key, where its value and children live.
+    fn node_end_position(&self, key: &[u8], key_extra_nibble: Option<u8>) -> (Vec<u8>, Option<u8>) {
+        let mut nibbles = node_prefix_nibbles(key, key_extra_nibble);
+        nibbles.extend(self.node_partial_key_nibbles(key, key_extra_nibble));
+        position_from_nibbles(&nibbles)
     }
 
     fn node_header(&self, key: &[u8], key_extra_nibble: Option<u8>) -> Vec<u8> {
+        let (end_key, end_extra) = self.node_end_position(key, key_extra_nibble);
+
         let two_msb: u8 = {
-            let has_stored_value = key_extra_nibble.is_none() && self.entries.contains_key(key);
-            let has_children = self.node_has_children(key, key_extra_nibble);
+            let has_stored_value = end_extra.is_none() && self.entries.contains_key(&end_key);
+            let has_children = self.node_has_children(&end_key, end_extra);
             match (has_stored_value, has_children) {
                 (false, false) => 0b00, // TODO: is that exact? specs say "Special case"?!?!
                 (true, false) => 0b01,
@@ -118,7 +318,9 @@ impl Trie {
         if pk_len >= 63 {
             pk_len -= 63;
             let mut out = vec![(two_msb << 6) + 63];
-            while pk_len > 255 {
+            // Emit 255 for every full continuation, always followed by a terminal byte < 255 (so a
+            // remainder of exactly 255 becomes `255, 0`). This is what `decode_node` expects.
+            while pk_len >= 255 {
                 pk_len -= 255;
                 out.push(255);
             }
@@ -131,58 +333,107 @@ impl Trie {
     }
 
     fn node_partial_key_nibbles(&self, key: &[u8], key_extra_nibble: Option<u8>) -> Vec<u8> {
-        Vec::new()  // TODO: FIXME: stub
+        // Standard Patricia path-compression: starting from this node's nibble position, follow the
+        // single-child chain, appending each forced nibble, until we reach a branch (>= 2 distinct
+        // child nibbles) or a position that stores a value.
+        let mut prefix = node_prefix_nibbles(key, key_extra_nibble);
+        let mut partial = Vec::new();
+
+        loop {
+            // A value stored exactly at the current position terminates the partial key. Entries are
+            // byte keys, so this can only happen on an even (byte-aligned) nibble boundary.
+            let at_value_position = prefix.len().is_multiple_of(2)
+                && self.entries.contains_key(&nibbles_to_key_floor(&prefix));
+            if at_value_position {
+                break;
+            }
+
+            let mut single = None;
+            let mut branch = false;
+            for (k, _) in self.entries_with_nibble_prefix(&prefix) {
+                let nibbles = bytes_to_nibbles(k);
+                if nibbles.len() <= prefix.len() {
+                    continue;
+                }
+                let next = nibbles[prefix.len()];
+                match single {
+                    None => single = Some(next),
+                    Some(n) if n == next => {}
+                    Some(_) => {
+                        branch = true;
+                        break;
+                    }
+                }
+            }
+
+            if branch {
+                break;
+            }
+
+            match single {
+                Some(next) => {
+                    prefix.push(next);
+                    partial.push(next);
+                }
+                None => break,
+            }
+        }
+
+        partial
     }
 
-    fn node_subvalue(&self, key: &[u8], key_extra_nibble: Option<u8>) -> Vec<u8> {
-        let encoded_stored_value = if key_extra_nibble.is_none() {
-            self.entries.get(key).cloned().unwrap_or(Vec::new())
+    fn node_subvalue(&self, key: &[u8], key_extra_nibble: Option<u8>) -> Vec<Vec<u8>> {
+        // The value and children live at the end of the partial key, not at the node's own nibble
+        // position, so that a compressed single-child chain encodes as one node.
+        let (end_key, end_extra) = self.node_end_position(key, key_extra_nibble);
+
+        let encoded_stored_value = if end_extra.is_none() {
+            self.entries.get(&end_key).cloned().unwrap_or(Vec::new())
         } else {
             Vec::new()
         }
         .encode();
 
-        let children_bitmap = self.node_children_bitmap(key, key_extra_nibble);
+        let children_bitmap = self.node_children_bitmap(&end_key, end_extra);
         if children_bitmap == 0 {
-            return encoded_stored_value;
+            return vec![encoded_stored_value];
         }
 
-        let mut out = children_bitmap.to_le_bytes().to_vec(); // TODO: LE? specs don't say anything, wtf
+        // Only the nibbles set in `children_bitmap` are emitted, each as a SCALE-length-prefixed
+        // blob of the child's Merkle value; `decode_node` walks the same bitmap to know how many
+        // entries follow and which slot each one belongs to.
+        let mut out = Vec::with_capacity(2 + children_bitmap.count_ones() as usize + 1);
+        out.push(children_bitmap.to_le_bytes().to_vec()); // TODO: LE? specs don't say anything, wtf
 
-        if let Some(extra) = key_extra_nibble {
-            for extra2 in 0..16 {
-                let mut subkey = key.to_vec();
-                subkey.push((extra << 4) | extra2);
-                let child_merkle_value = self.merkle_value(&subkey, None);
-                out.extend(child_merkle_value.encode());
-            }
-        } else {
-            for extra in 0..16 {
-                let child_merkle_value = self.merkle_value(key, Some(extra));
-                out.extend(child_merkle_value.encode());
+        for n in 0..16u8 {
+            if children_bitmap & (1 << (15 - n)) == 0 {
+                continue;
             }
+
+            let child_merkle_value = if let Some(extra) = end_extra {
+                let mut subkey = end_key.clone();
+                subkey.push((extra << 4) | n);
+                self.merkle_value(&subkey, None)
+            } else {
+                self.merkle_value(&end_key, Some(n))
+            };
+
+            out.push(child_merkle_value.to_vec().encode());
         }
 
-        out.extend(encoded_stored_value);
+        out.push(encoded_stored_value);
         out
     }
 
     fn node_children_bitmap(&self, key: &[u8], key_extra_nibble: Option<u8>) -> u16 {
+        let prefix = node_prefix_nibbles(key, key_extra_nibble);
         let mut out = 0u16;
 
-        if let Some(key_extra_nibble) = key_extra_nibble {
-            for n in 0..16 {
-                let mut subkey = key.to_vec();
-                subkey.push((key_extra_nibble << 4) | n);
-                if self.node_has_children(&subkey, None) {
-                    out |= 1 << (15 - n);
-                }
-            }
-        } else {
-            for n in 0..16 {
-                if self.node_has_children(key, Some(n)) {
-                    out |= 1 << (15 - n);
-                }
+        for n in 0..16u8 {
+            let mut child = prefix.clone();
+            child.push(n);
+            if self.entries_with_nibble_prefix(&child).next().is_some() {
+                out |= 1 << (15 - n);
             }
         }
 
@@ -190,10 +441,423 @@ impl Trie {
     }
 
     fn node_has_children(&self, key: &[u8], key_extra_nibble: Option<u8>) -> bool {
-        let mut start = key.to_vec();
-        let mut end = key.to_vec();
-        start.push(0);
-        end.push(255);
-        self.entries.range(start..=end).next().is_some()
+        let prefix = node_prefix_nibbles(key, key_extra_nibble);
+        self.entries_with_nibble_prefix(&prefix)
+            .any(|(k, _)| bytes_to_nibbles(k).len() > prefix.len())
     }
-}
\ No newline at end of file
+
+    /// Iterates over all the entries whose key, expanded to nibbles, starts with `prefix`.
+    ///
+    /// `prefix` is cloned into the closure rather than borrowed, so the returned iterator's
+    /// lifetime is tied only to `self`, not to whatever local `prefix` happens to live in at the
+    /// call site (which is often shorter-lived than the iterator itself, e.g. a tail expression).
+    fn entries_with_nibble_prefix<'a>(
+        &'a self,
+        prefix: &[u8],
+    ) -> impl Iterator<Item = (&'a Vec<u8>, &'a Vec<u8>)> {
+        let prefix = prefix.to_vec();
+        self.entries
+            .range(nibbles_to_key_floor(&prefix)..)
+            .take_while(move |(k, _)| bytes_to_nibbles(k).starts_with(&prefix))
+    }
+}
+
+/// Expands a byte slice into its sequence of nibbles, most significant first.
+fn bytes_to_nibbles(bytes: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        out.push(byte >> 4);
+        out.push(byte & 0xf);
+    }
+    out
+}
+
+/// Returns the node's nibble position: the nibbles of `key` followed by the optional extra nibble.
+fn node_prefix_nibbles(key: &[u8], key_extra_nibble: Option<u8>) -> Vec<u8> {
+    let mut prefix = bytes_to_nibbles(key);
+    if let Some(extra) = key_extra_nibble {
+        prefix.push(extra);
+    }
+    prefix
+}
+
+/// Packs a nibble sequence into the smallest byte key sharing it as a prefix. An odd trailing
+/// nibble becomes the high nibble of the last byte, with the low nibble left at zero.
+fn nibbles_to_key_floor(nibbles: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(nibbles.len().div_ceil(2));
+    for chunk in nibbles.chunks(2) {
+        if chunk.len() == 2 {
+            out.push((chunk[0] << 4) | chunk[1]);
+        } else {
+            out.push(chunk[0] << 4);
+        }
+    }
+    out
+}
+
+/// Splits a nibble position into the `(key, key_extra_nibble)` pair used throughout [`Trie`].
+fn position_from_nibbles(nibbles: &[u8]) -> (Vec<u8>, Option<u8>) {
+    if nibbles.len().is_multiple_of(2) {
+        (nibbles_to_key_floor(nibbles), None)
+    } else {
+        let (head, last) = nibbles.split_at(nibbles.len() - 1);
+        (nibbles_to_key_floor(head), Some(last[0]))
+    }
+}
+
+/// Computes the Merkle value of an already-encoded node value, matching the inline-vs-hashed rule
+/// of [`Trie::merkle_value`]: the root and any node value of at least 32 bytes are hashed, shorter
+/// node values are right-aligned into the 32-byte output verbatim.
+fn merkle_value_of<H: HashFunction>(node_value: &[u8], is_root: bool) -> [u8; 32] {
+    if is_root || node_value.len() >= 32 {
+        H::hash(node_value)
+    } else {
+        let mut out = [0; 32];
+        out[(32 - node_value.len())..].copy_from_slice(node_value);
+        out
+    }
+}
+
+/// Error that can happen while parsing a node value with [`decode_node`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The byte slice ended before the node was fully parsed.
+    TooShort,
+    /// A child reference couldn't be decoded, or wasn't the expected 32-byte hash.
+    InvalidChild,
+    /// The SCALE-encoded stored value couldn't be decoded.
+    InvalidValue,
+}
+
+/// Reference to a child node found inside a decoded node value.
+///
+/// The encoder always stores children as their 32-byte Merkle value (see [`Trie::node_subvalue`]),
+/// so a child is always referenced by that 32-byte value: the hash of its node value, or, when the
+/// node value is shorter than 32 bytes, the node value itself right-aligned and zero-padded. There
+/// is no separate variant for inline children.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChildRef {
+    /// Merkle value of the child.
+    Hash([u8; 32]),
+}
+
+/// Structured representation of a node value, as produced by [`decode_node`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedNode {
+    /// Partial key of the node, as a sequence of nibbles.
+    pub partial_key: Vec<u8>,
+    /// Children of the node, indexed by their nibble.
+    pub children: [Option<ChildRef>; 16],
+    /// Value stored at the node, if any.
+    pub value: Option<Vec<u8>>,
+}
+
+/// Parses a node value back into its structured form, inverting [`Trie::node_value`].
+pub fn decode_node(bytes: &[u8]) -> Result<DecodedNode, DecodeError> {
+    fn read_byte(input: &mut &[u8]) -> Result<u8, DecodeError> {
+        let (first, rest) = input.split_first().ok_or(DecodeError::TooShort)?;
+        *input = rest;
+        Ok(*first)
+    }
+
+    let mut input = bytes;
+
+    let header = read_byte(&mut input)?;
+    let two_msb = header >> 6;
+    let has_stored_value = two_msb & 0b01 != 0;
+    let has_children = two_msb & 0b10 != 0;
+
+    let mut partial_key_len = usize::from(header & 0b0011_1111);
+    if partial_key_len == 63 {
+        loop {
+            let extra = read_byte(&mut input)?;
+            partial_key_len += usize::from(extra);
+            if extra != 255 {
+                break;
+            }
+        }
+    }
+
+    let partial_key_bytes = partial_key_len.div_ceil(2);
+    if input.len() < partial_key_bytes {
+        return Err(DecodeError::TooShort);
+    }
+    let (packed_partial_key, rest) = input.split_at(partial_key_bytes);
+    input = rest;
+
+    // Undo the even/odd nibble packing performed by `node_value`.
+    let mut partial_key = Vec::with_capacity(partial_key_len);
+    if partial_key_len % 2 == 1 {
+        partial_key.push(packed_partial_key[0] & 0x0f);
+        for byte in &packed_partial_key[1..] {
+            partial_key.push(byte >> 4);
+            partial_key.push(byte & 0x0f);
+        }
+    } else {
+        for byte in packed_partial_key {
+            partial_key.push(byte >> 4);
+            partial_key.push(byte & 0x0f);
+        }
+    }
+
+    let mut children: [Option<ChildRef>; 16] = Default::default();
+    if has_children {
+        if input.len() < 2 {
+            return Err(DecodeError::TooShort);
+        }
+        let children_bitmap = u16::from_le_bytes([input[0], input[1]]);
+        input = &input[2..];
+
+        // Only the nibbles set in the bitmap have a blob to read; everything else stays `None`.
+        for (n, child) in children.iter_mut().enumerate() {
+            if children_bitmap & (1 << (15 - n)) == 0 {
+                continue;
+            }
+            let blob = Vec::<u8>::decode(&mut input).map_err(|_| DecodeError::InvalidChild)?;
+            let hash = <[u8; 32]>::try_from(&blob[..]).map_err(|_| DecodeError::InvalidChild)?;
+            *child = Some(ChildRef::Hash(hash));
+        }
+    }
+
+    // The stored value is always present as a SCALE-encoded byte vector, even when empty.
+    let stored_value = Vec::<u8>::decode(&mut input).map_err(|_| DecodeError::InvalidValue)?;
+    let value = has_stored_value.then_some(stored_value);
+
+    Ok(DecodedNode {
+        partial_key,
+        children,
+        value,
+    })
+}
+
+/// Checks a Merkle proof produced by [`Trie::prove`] against a 32-byte `root`.
+///
+/// Returns the value stored at `key` on success, or `None` when the proof establishes that the key
+/// is absent from the trie.
+pub fn verify_proof<H: HashFunction>(
+    root: &[u8; 32],
+    key: &[u8],
+    proof: &[Vec<u8>],
+) -> Result<Option<Vec<u8>>, ProofError> {
+    let key_nibbles = bytes_to_nibbles(key);
+    let mut expected = *root;
+    let mut depth = 0;
+
+    for (index, node_value) in proof.iter().enumerate() {
+        if merkle_value_of::<H>(node_value, index == 0) != expected {
+            return Err(ProofError::HashMismatch);
+        }
+
+        let decoded = decode_node(node_value).map_err(|_| ProofError::MalformedNode)?;
+
+        // Consume the node's partial key. If it overruns the key or diverges, the key is forced
+        // onto a different path and is therefore proven absent.
+        let end = depth + decoded.partial_key.len();
+        if end > key_nibbles.len() || key_nibbles[depth..end] != decoded.partial_key[..] {
+            return Ok(None);
+        }
+
+        if end == key_nibbles.len() {
+            return Ok(decoded.value);
+        }
+
+        match &decoded.children[usize::from(key_nibbles[end])] {
+            Some(ChildRef::Hash(child)) => {
+                expected = *child;
+                depth = end + 1;
+            }
+            // The owning child is absent: the key is proven not to be in the trie.
+            None => return Ok(None),
+        }
+    }
+
+    Err(ProofError::Incomplete)
+}
+/// Hashes the concatenation of `chunks` with Keccak-256 (the Ethereum variant, `0x01` padding),
+/// absorbing into the sponge incrementally so the full input never needs to be materialized.
+fn keccak256(chunks: impl Iterator<Item = impl AsRef<[u8]>>) -> [u8; 32] {
+    /// Rate of Keccak-256 in bytes (1600-bit state minus the 512-bit capacity).
+    const RATE: usize = 136;
+
+    let mut state = [0u64; 25];
+    let mut block = [0u8; RATE];
+    let mut filled = 0;
+
+    let absorb = |state: &mut [u64; 25], block: &[u8; RATE]| {
+        for (lane, bytes) in block.chunks_exact(8).enumerate() {
+            state[lane] ^= u64::from_le_bytes(bytes.try_into().unwrap());
+        }
+        keccak_f1600(state);
+    };
+
+    for chunk in chunks {
+        let mut data = chunk.as_ref();
+        while !data.is_empty() {
+            let take = core::cmp::min(RATE - filled, data.len());
+            block[filled..filled + take].copy_from_slice(&data[..take]);
+            filled += take;
+            data = &data[take..];
+            if filled == RATE {
+                absorb(&mut state, &block);
+                filled = 0;
+            }
+        }
+    }
+
+    // Final block with the `pad10*1` padding and Keccak's `0x01` domain byte.
+    let mut last = [0u8; RATE];
+    last[..filled].copy_from_slice(&block[..filled]);
+    last[filled] ^= 0x01;
+    last[RATE - 1] ^= 0x80;
+    absorb(&mut state, &last);
+
+    let mut out = [0u8; 32];
+    for (lane, bytes) in out.chunks_exact_mut(8).enumerate() {
+        bytes.copy_from_slice(&state[lane].to_le_bytes());
+    }
+    out
+}
+
+/// The Keccak-f[1600] permutation.
+fn keccak_f1600(state: &mut [u64; 25]) {
+    const RC: [u64; 24] = [
+        0x0000000000000001, 0x0000000000008082, 0x800000000000808a, 0x8000000080008000,
+        0x000000000000808b, 0x0000000080000001, 0x8000000080008081, 0x8000000000008009,
+        0x000000000000008a, 0x0000000000000088, 0x0000000080008009, 0x000000008000000a,
+        0x000000008000808b, 0x800000000000008b, 0x8000000000008089, 0x8000000000008003,
+        0x8000000000008002, 0x8000000000000080, 0x000000000000800a, 0x800000008000000a,
+        0x8000000080008081, 0x8000000000008080, 0x0000000080000001, 0x8000000080008008,
+    ];
+    const ROTATIONS: [u32; 24] = [
+        1, 3, 6, 10, 15, 21, 28, 36, 45, 55, 2, 14, 27, 41, 56, 8, 25, 43, 62, 18, 39, 61, 20, 44,
+    ];
+    const PERMUTATION: [usize; 24] = [
+        10, 7, 11, 17, 18, 3, 5, 16, 8, 21, 24, 4, 15, 23, 19, 13, 12, 2, 20, 14, 22, 9, 6, 1,
+    ];
+
+    for &round_constant in RC.iter() {
+        // Theta.
+        let mut c = [0u64; 5];
+        for x in 0..5 {
+            c[x] = state[x] ^ state[x + 5] ^ state[x + 10] ^ state[x + 15] ^ state[x + 20];
+        }
+        for x in 0..5 {
+            let d = c[(x + 4) % 5] ^ c[(x + 1) % 5].rotate_left(1);
+            for y in 0..5 {
+                state[x + 5 * y] ^= d;
+            }
+        }
+
+        // Rho and pi.
+        let mut last = state[1];
+        for i in 0..24 {
+            let target = PERMUTATION[i];
+            let moved = state[target];
+            state[target] = last.rotate_left(ROTATIONS[i]);
+            last = moved;
+        }
+
+        // Chi.
+        for y in 0..5 {
+            let row = [
+                state[5 * y],
+                state[5 * y + 1],
+                state[5 * y + 2],
+                state[5 * y + 3],
+                state[5 * y + 4],
+            ];
+            for x in 0..5 {
+                state[5 * y + x] = row[x] ^ (!row[(x + 1) % 5] & row[(x + 2) % 5]);
+            }
+        }
+
+        // Iota.
+        state[0] ^= round_constant;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_trie() -> Trie<Blake2b256> {
+        let mut trie = Trie::new();
+        trie.insert(b"alfa".to_vec(), b"1".to_vec());
+        trie.insert(b"alfabet".to_vec(), b"2".to_vec());
+        trie.insert(b"bravo".to_vec(), b"3".to_vec());
+        trie
+    }
+
+    #[test]
+    fn proof_roundtrip_for_present_key() {
+        let trie = sample_trie();
+        let root = trie.root_merkle_value();
+
+        let proof = trie.prove(b"alfabet");
+        let value = verify_proof::<Blake2b256>(&root, b"alfabet", &proof).unwrap();
+        assert_eq!(value, Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn proof_roundtrip_for_absent_key() {
+        let trie = sample_trie();
+        let root = trie.root_merkle_value();
+
+        let proof = trie.prove(b"charlie");
+        let value = verify_proof::<Blake2b256>(&root, b"charlie", &proof).unwrap();
+        assert_eq!(value, None);
+    }
+
+    #[test]
+    fn branch_node_encodes_only_present_children() {
+        // The three keys share nibble `1`, then two of them (`0x12,..`) continue to share nibble
+        // `2` while the third (`0x13,..`) diverges onto nibble `3`: a branch with only 2 children.
+        let mut trie = Trie::<Blake2b256>::new();
+        trie.insert(vec![0x12, 0x34], vec![1]);
+        trie.insert(vec![0x12, 0x56], vec![2]);
+        trie.insert(vec![0x13, 0x00], vec![3]);
+
+        let root_value: Vec<u8> = trie.node_value(&[], None).flatten().collect();
+        // A 2-way branch should be well under the ~512 bytes a full, unconditional 16-way fan-out
+        // would cost.
+        assert!(root_value.len() < 150, "node value is {} bytes", root_value.len());
+
+        let decoded = decode_node(&root_value).unwrap();
+        assert_eq!(decoded.partial_key, vec![1]);
+        let present: Vec<usize> = decoded
+            .children
+            .iter()
+            .enumerate()
+            .filter_map(|(n, child)| child.is_some().then_some(n))
+            .collect();
+        assert_eq!(present, vec![2, 3]);
+    }
+
+    #[test]
+    fn cache_invalidation_keeps_root_in_sync() {
+        let mut trie = sample_trie();
+        let root_before = trie.root_merkle_value();
+
+        trie.insert(b"bravo2".to_vec(), b"4".to_vec());
+        let root_after_insert = trie.root_merkle_value();
+        assert_ne!(root_before, root_after_insert);
+
+        trie.remove(b"bravo2");
+        let root_after_remove = trie.root_merkle_value();
+        assert_eq!(root_before, root_after_remove);
+    }
+
+    #[test]
+    fn remove_prefix_invalidates_cache() {
+        let mut trie = sample_trie();
+
+        // Prime the cache along the path to `alfabet` before the subtree is removed.
+        let _ = trie.merkle_value(b"alfa", None);
+        trie.remove_prefix(b"alfa");
+        let root_after_removal = trie.root_merkle_value();
+
+        let mut expected = Trie::<Blake2b256>::new();
+        expected.insert(b"bravo".to_vec(), b"3".to_vec());
+        assert_eq!(root_after_removal, expected.root_merkle_value());
+    }
+}